@@ -1,6 +1,7 @@
 use clap::Parser;
 use humansize::{file_size_opts as options, FileSize};
 use image::{open, GenericImageView};
+use oxipng::{optimize_from_memory, Options};
 use rayon::prelude::*;
 use walkdir::WalkDir;
 
@@ -20,9 +21,79 @@ struct Args {
     #[clap(short, long, default_value_t = 0)]
     jobs: u8,
 
+    /// Which optimizer backend to use: spawn the external tools
+    /// (optipng/convert/advpng/oxipng) or optimize in-process via liboxipng
+    #[clap(long, value_enum, default_value_t = Backend::ExternalTools)]
+    backend: Backend,
+
+    /// oxipng optimization level, only used by the liboxipng backend
+    #[clap(long, default_value_t = 6)]
+    level: u8,
+
+    /// Ancillary chunk stripping policy: "none" keeps every chunk, "safe"
+    /// drops text/timestamp metadata but keeps color-critical chunks
+    /// (cICP/iCCP/sRGB/pHYs and APNG frame chunks), "all" strips everything
+    #[clap(long, value_enum, default_value_t = StripMode::Safe)]
+    strip: StripMode,
+
+    /// Run the full optimization pipeline on temp copies and report the
+    /// achievable savings, without touching any of the original files
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Restore the original file's permissions and modification time after
+    /// optimizing, so a losslessly recompressed PNG doesn't look "touched"
+    #[clap(long)]
+    preserve: bool,
+
+    /// Run an extra Zopfli deflate pass for a few extra percent of
+    /// compression, at a large CPU cost; off by default, pairs well with
+    /// lowering --jobs
+    #[clap(long)]
+    zopfli: bool,
+
+    /// Zopfli iteration count, only used when --zopfli is set
+    #[clap(long, default_value_t = 15)]
+    zopfli_iterations: u8,
+
     paths: Vec<String>,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Backend {
+    ExternalTools,
+    #[value(name = "liboxipng")]
+    LibOxipng,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Backend::ExternalTools => "external-tools",
+            Backend::LibOxipng => "liboxipng",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum StripMode {
+    None,
+    Safe,
+    All,
+}
+
+impl std::fmt::Display for StripMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            StripMode::None => "none",
+            StripMode::Safe => "safe",
+            StripMode::All => "all",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 static EXEC_OPTIPNG: &str = "optipng";
 static EXEC_IMAGEMAGIC: &str = "convert";
 static EXEC_ADVPNG: &str = "advpng";
@@ -56,29 +127,51 @@ fn main() {
             .unwrap()
     );
 
-    assert_optimizers_are_available();
+    if cli.backend == Backend::ExternalTools {
+        assert_optimizers_are_available();
+    }
 
     // optimize
-    all_png_files
-        .par_iter()
-        .map(Image::new)
-        .for_each(|mut img| img.optimize());
-
     let total_file_size_after = all_png_files
-        .iter()
-        .flat_map(std::fs::metadata)
-        .map(|metadata| metadata.len())
+        .par_iter()
+        .map(|path| {
+            Image::new(
+                path,
+                cli.backend,
+                cli.level,
+                cli.strip,
+                cli.dry_run,
+                cli.preserve,
+                cli.zopfli,
+                cli.zopfli_iterations,
+            )
+            .optimize()
+        })
         .sum::<u64>();
 
-    println!(
-        "Reduced size of  {} files to a total size of: {}",
-        all_png_files.len(),
-        total_file_size_after
-            .file_size(options::CONVENTIONAL)
-            .unwrap()
-    );
+    let size_delta = total_file_size_after as i64 - total_file_size_before as i64;
+    let percent_delta = (size_delta as f64 / total_file_size_before as f64) * 100_f64;
 
-    println!("{}", total_file_size_after - total_file_size_before);
+    if cli.dry_run {
+        println!(
+            "would save {} ({:.2}%) across {} files",
+            ((-size_delta).max(0) as u64)
+                .file_size(options::CONVENTIONAL)
+                .unwrap(),
+            -percent_delta,
+            all_png_files.len(),
+        );
+    } else {
+        println!(
+            "Reduced size of  {} files to a total size of: {}",
+            all_png_files.len(),
+            total_file_size_after
+                .file_size(options::CONVENTIONAL)
+                .unwrap()
+        );
+
+        println!("{}", size_delta);
+    }
 }
 
 /// check that all input paths are present/valid, if not, terminate
@@ -125,32 +218,113 @@ fn images_are_identical(image1: &PathBuf, image2: &PathBuf) -> bool {
     pixels_1.eq(pixels_2)
 }
 
+// `image::open(...).pixels()` only ever decodes the first frame of a PNG, so
+// it cannot tell an APNG from a still image; scan the chunk stream by hand
+// instead. An `acTL` chunk appearing before the first `IDAT` is what the APNG
+// spec uses to signal animation (the 8-byte PNG signature is followed by a
+// stream of length(4)+type(4)+data(length)+crc(4) chunk records).
+fn is_apng(path: &PathBuf) -> bool {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+
+    is_apng_bytes(&bytes)
+}
+
+// pulled out of is_apng so the chunk-scanning logic can be unit tested
+// directly against byte fixtures instead of real files on disk
+fn is_apng_bytes(bytes: &[u8]) -> bool {
+    const PNG_SIGNATURE: &[u8; 8] = &[137, 80, 78, 71, 13, 10, 26, 10];
+
+    if bytes.len() < 8 || &bytes[0..8] != PNG_SIGNATURE {
+        return false;
+    }
+
+    let mut offset = 8;
+    while offset + 8 <= bytes.len() {
+        let length = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+        let chunk_type = &bytes[offset + 4..offset + 8];
+
+        match chunk_type {
+            b"acTL" => return true,
+            b"IDAT" => return false,
+            _ => {}
+        }
+
+        // length + type(4) + data(length) + crc(4), bail out rather than
+        // overflow/wrap into an infinite loop on a truncated or malformed
+        // length field
+        let Some(next) = offset.checked_add(8 + length + 4) else {
+            return false;
+        };
+        offset = next;
+    }
+
+    false
+}
+
 struct Image<'a> {
     path: &'a PathBuf,
+    backend: Backend,
+    level: u8,
+    strip: StripMode,
+    dry_run: bool,
+    preserve: bool,
+    zopfli: bool,
+    zopfli_iterations: u8,
 }
 
 impl<'a> Image<'a> {
-    fn new(path: &'a PathBuf) -> Self {
-        Image { path }
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        path: &'a PathBuf,
+        backend: Backend,
+        level: u8,
+        strip: StripMode,
+        dry_run: bool,
+        preserve: bool,
+        zopfli: bool,
+        zopfli_iterations: u8,
+    ) -> Self {
+        Image {
+            path,
+            backend,
+            level,
+            strip,
+            dry_run,
+            preserve,
+            zopfli,
+            zopfli_iterations,
+        }
     }
-    fn run_imagemagick(&self, tmp_path: &PathBuf) -> bool {
+    fn run_imagemagick(&self, source: &PathBuf, tmp_path: &PathBuf) -> bool {
         // copy files
-        std::fs::copy(&self.path, tmp_path).expect(&format!(
-            "{} to {}",
-            &self.path.display(),
-            tmp_path.display()
-        ));
+        std::fs::copy(source, tmp_path)
+            .unwrap_or_else(|_| panic!("{} to {}", source.display(), tmp_path.display()));
         let mut cmd = Command::new(EXEC_IMAGEMAGIC);
-        cmd.args(["-strip", "-define", "png:color-type=6"])
-            .args([self.path, tmp_path]);
+        cmd.args(["-define", "png:color-type=6"]);
+        match self.strip {
+            StripMode::None => {}
+            // imagemagick's -strip removes every ancillary chunk, so name the
+            // text/timestamp chunks explicitly instead, leaving cICP/iCCP/sRGB/
+            // pHYs and APNG frame chunks (fcTL/fdAT/acTL) untouched
+            StripMode::Safe => {
+                cmd.args(["-define", "png:exclude-chunk=date,time,text,ztxt,itxt"]);
+            }
+            StripMode::All => {
+                cmd.arg("-strip");
+            }
+        }
+        cmd.args([source, tmp_path]);
 
         // do not discard output
         cmd.status().unwrap().success()
     }
 
-    fn run_optipng(&self, tmp_path: &PathBuf) -> bool {
+    fn run_optipng(&self, source: &PathBuf, tmp_path: &PathBuf) -> bool {
         // copy files
-        std::fs::copy(&self.path, tmp_path).expect("failed to copy");
+        std::fs::copy(source, tmp_path).expect("failed to copy");
         let mut cmd = Command::new(EXEC_OPTIPNG);
         cmd.args(["-q", "-o5", "-nb", "-nc", "-np"]).arg(tmp_path);
 
@@ -174,44 +348,121 @@ impl<'a> Image<'a> {
         v.into_iter().all(|v| v)
     }
 
-    fn run_oxipng(&self, tmp_path: &PathBuf) -> bool {
+    fn run_oxipng(&self, source: &PathBuf, tmp_path: &PathBuf) -> bool {
         // copy files
-        std::fs::copy(&self.path, tmp_path).expect("failed to copy");
+        std::fs::copy(source, tmp_path).expect("failed to copy");
         let mut cmd = Command::new(EXEC_OXIPNG);
-        cmd.args(["--nc", "--np", "-o6", "--quiet"]).arg(tmp_path);
+        cmd.args(["--nc", "--np", "-o6", "--quiet"])
+            .args(["--strip", &self.strip.to_string()])
+            .arg(tmp_path);
 
         // discard output
         cmd.output().unwrap().status.success()
     }
 
-    fn verify_image(&mut self, backup_image: &PathBuf) {
-        let pixel_identical: bool = images_are_identical(self.path, backup_image);
+    // slow final deflate pass; source is the best candidate accepted so far,
+    // so the pass refines it rather than whatever rejected output the prior
+    // tool may have left in tmp_path
+    fn run_oxipng_zopfli(&self, source: &PathBuf, tmp_path: &PathBuf) -> bool {
+        std::fs::copy(source, tmp_path).expect("failed to copy");
+        let mut cmd = Command::new(EXEC_OXIPNG);
+        cmd.args(["--nc", "--np", "--quiet", "--zopfli"])
+            .args(["--iterations", &self.zopfli_iterations.to_string()])
+            .args(["--strip", &self.strip.to_string()])
+            .arg(tmp_path);
 
-        let size_new = std::fs::metadata(self.path).unwrap().len();
-        let size_old = std::fs::metadata(backup_image).unwrap().len();
+        // discard output
+        cmd.output().unwrap().status.success()
+    }
+
+    // run oxipng in-process on a buffer read once from disk, avoiding the
+    // temp-file copy and subprocess spawn the run_* methods above rely on
+    fn run_liboxipng(&self, source: &PathBuf, tmp_path: &PathBuf) -> bool {
+        let data = match std::fs::read(source) {
+            Ok(data) => data,
+            Err(_) => return false,
+        };
+
+        let mut options = Options::from_preset(self.level);
+        options.strip = match self.strip {
+            StripMode::None => oxipng::StripChunks::None,
+            StripMode::Safe => oxipng::StripChunks::Safe,
+            StripMode::All => oxipng::StripChunks::All,
+        };
+        if self.zopfli {
+            options.deflater = oxipng::Deflater::Zopfli(oxipng::ZopfliOptions {
+                iteration_count: std::num::NonZeroU64::new(self.zopfli_iterations.max(1) as u64)
+                    .unwrap(),
+                ..Default::default()
+            });
+        }
+
+        match optimize_from_memory(&data, &options) {
+            Ok(optimized) if optimized.len() < data.len() => {
+                std::fs::write(tmp_path, optimized).is_ok()
+            }
+            Ok(_) => std::fs::copy(source, tmp_path).is_ok(),
+            Err(_) => std::fs::copy(source, tmp_path).is_ok(),
+        }
+    }
+
+    // current_path is the candidate chain's running "accepted" file (the real
+    // self.path outside dry-run, or a private scratch copy during dry-run);
+    // candidate_path (tmp_path) holds what the last run_* call produced. On
+    // acceptance candidate_path is always copied back into current_path, so
+    // the next tool in the pipeline keeps chaining off the best result seen
+    // so far regardless of dry_run.
+    fn verify_image(&self, current_path: &PathBuf, candidate_path: &PathBuf) {
+        let pixel_identical: bool = images_are_identical(current_path, candidate_path);
+
+        let size_new = std::fs::metadata(candidate_path).unwrap().len();
+        let size_old = std::fs::metadata(current_path).unwrap().len();
         let image_got_smaller: bool = size_new < size_old;
 
         match (pixel_identical, image_got_smaller) {
             (true, true) => {
-                // if we got smaller, overwrite backup with smaller version
-                std::fs::copy(self.path, backup_image).unwrap();
+                // candidate verified identical and smaller: accept it
+                std::fs::copy(candidate_path, current_path).unwrap();
             }
             (true, false) => {
                 //println!("failed to optimize: {} to {}", size_old, size_new);
             }
             (false, true) => {
-                // image was altered, BAD; don't overwrite, dorollback
+                // candidate is smaller but pixel data changed; reject it
                 println!("image altered! :(");
-                std::fs::copy(backup_image, self.path).unwrap();
             }
             (false, false) => {
-                // wtf!
-                panic!();
+                // candidate neither shrank the file nor stayed identical; reject it
             }
         }
     }
-    fn optimize(&mut self) {
-        let original_size = std::fs::metadata(&self.path).unwrap().len();
+    // reapply the original file's permissions and mtime, so a losslessly
+    // recompressed PNG doesn't show up as "modified" to tools that key off them
+    fn restore_attrs(&self, original_metadata: &std::fs::Metadata) {
+        if let Err(e) = std::fs::set_permissions(self.path, original_metadata.permissions()) {
+            eprintln!(
+                "failed to restore permissions on {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+        match original_metadata.modified() {
+            Ok(mtime) => {
+                if let Err(e) = std::fs::File::open(self.path).and_then(|f| f.set_modified(mtime)) {
+                    eprintln!("failed to restore mtime on {}: {}", self.path.display(), e);
+                }
+            }
+            Err(e) => eprintln!(
+                "failed to read original mtime for {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+
+    fn optimize(&mut self) -> u64 {
+        let original_metadata = std::fs::metadata(self.path).unwrap();
+        let original_size = original_metadata.len();
         let mut iteration = 0;
 
         let tmp_path = {
@@ -223,37 +474,100 @@ impl<'a> Image<'a> {
             t
         };
 
+        // current_path is what the run_* calls copy their source from (run_advpng
+        // instead works on tmp_path in place) and what verify_image accepts
+        // candidates into; outside dry-run it just
+        // is self.path, but in dry-run it's a private scratch copy so tools
+        // keep chaining off each other's accepted output without ever
+        // touching the real file on disk
+        let current_path = if self.dry_run {
+            let mut c = self.path.clone();
+            c.set_file_name(format!(
+                "{}_dryrun.png",
+                &self.path.file_stem().unwrap().to_str().unwrap()
+            ));
+            std::fs::copy(self.path, &c).unwrap();
+            c
+        } else {
+            self.path.clone()
+        };
+
+        let is_animated = is_apng(self.path);
+        if is_animated {
+            println!(
+                "{} is an APNG, skipping imagemagick/optipng/advpng to preserve its animation",
+                self.path.display()
+            );
+        }
+
         let mut size_before = original_size;
         let mut size_after = 0;
-        let mut perc_delta: f64 = 0.0;
-        let mut size_delta: i64 = 0;
         while size_before > size_after || iteration == 0 {
             iteration += 1;
-            size_before = std::fs::metadata(&self.path).unwrap().len();
-
-            self.run_imagemagick(&tmp_path);
-            self.verify_image(&tmp_path);
-
-            self.run_optipng(&tmp_path);
-            self.verify_image(&tmp_path);
-
-            self.run_advpng(&tmp_path);
-            self.verify_image(&tmp_path);
-
-            self.run_oxipng(&tmp_path);
-            self.verify_image(&tmp_path);
+            size_before = std::fs::metadata(&current_path).unwrap().len();
+
+            match self.backend {
+                Backend::ExternalTools if is_animated => {
+                    // imagemagick flattens/regenerates the color type and advpng
+                    // only touches the IDAT stream it finds, neither is safe for
+                    // an APNG's fcTL/fdAT frames; oxipng leaves unknown chunks
+                    // alone, so it's the only animation-safe external tool here
+                    self.run_oxipng(&current_path, &tmp_path);
+                    self.verify_image(&current_path, &tmp_path);
+
+                    if self.zopfli {
+                        self.run_oxipng_zopfli(&current_path, &tmp_path);
+                        self.verify_image(&current_path, &tmp_path);
+                    }
+                }
+                Backend::ExternalTools => {
+                    self.run_imagemagick(&current_path, &tmp_path);
+                    self.verify_image(&current_path, &tmp_path);
+
+                    self.run_optipng(&current_path, &tmp_path);
+                    self.verify_image(&current_path, &tmp_path);
+
+                    self.run_advpng(&tmp_path);
+                    self.verify_image(&current_path, &tmp_path);
+
+                    self.run_oxipng(&current_path, &tmp_path);
+                    self.verify_image(&current_path, &tmp_path);
+
+                    if self.zopfli {
+                        self.run_oxipng_zopfli(&current_path, &tmp_path);
+                        self.verify_image(&current_path, &tmp_path);
+                    }
+                }
+                Backend::LibOxipng => {
+                    self.run_liboxipng(&current_path, &tmp_path);
+                    self.verify_image(&current_path, &tmp_path);
+                }
+            }
 
-            size_after = std::fs::metadata(&self.path).unwrap().len();
-            size_delta = size_after as i64 - size_before as i64;
-            perc_delta = (size_delta as f64 / size_before as f64) * 100_f64;
+            size_after = std::fs::metadata(&current_path).unwrap().len();
         }
+        let size_delta = size_after as i64 - original_size as i64;
+        let perc_delta = (size_delta as f64 / original_size as f64) * 100_f64;
+
         if tmp_path.exists() {
             // clean up
             std::fs::remove_file(tmp_path).unwrap();
         }
 
+        if self.dry_run {
+            std::fs::remove_file(&current_path).unwrap();
+        } else if self.preserve {
+            self.restore_attrs(&original_metadata);
+        }
+
+        let label = if self.dry_run {
+            "would optimize"
+        } else {
+            "optimized"
+        };
         println!(
-            "optimized {}, from {}b to {}b, {}, {}",
+            "{} {}, from {}b to {}b, {}, {}",
+            label,
             self.path.display(),
             original_size,
             size_after,
@@ -265,6 +579,51 @@ impl<'a> Image<'a> {
                 };
                 t
             },
-        )
+        );
+        size_after
+    }
+}
+
+#[cfg(test)]
+mod is_apng_tests {
+    use super::is_apng_bytes;
+
+    const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+    fn chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(chunk_type);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&[0, 0, 0, 0]); // crc, unchecked by is_apng_bytes
+        out
+    }
+
+    #[test]
+    fn detects_actl_before_idat_as_animated() {
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        bytes.extend(chunk(b"IHDR", &[0; 13]));
+        bytes.extend(chunk(b"acTL", &[0; 8]));
+        bytes.extend(chunk(b"IDAT", &[0; 4]));
+
+        assert!(is_apng_bytes(&bytes));
+    }
+
+    #[test]
+    fn static_png_without_actl_is_not_animated() {
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        bytes.extend(chunk(b"IHDR", &[0; 13]));
+        bytes.extend(chunk(b"IDAT", &[0; 4]));
+
+        assert!(!is_apng_bytes(&bytes));
+    }
+
+    #[test]
+    fn truncated_garbage_is_not_animated_and_does_not_panic() {
+        let bytes = [137, 80, 78, 71, 1, 2, 3];
+        assert!(!is_apng_bytes(&bytes));
+
+        let bytes = [0u8; 4];
+        assert!(!is_apng_bytes(&bytes));
     }
 }